@@ -0,0 +1,105 @@
+//! A durable, append-only backlog of writes that failed to commit to
+//! InfluxDB. Records are kept on disk so a network blip or an InfluxDB
+//! outage doesn't silently drop polled data: failed batches are appended
+//! here, and the next run flushes whatever is still pending before it
+//! writes anything fresh.
+
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::DataPoint;
+
+const BACKLOG_FILE_NAME: &str = "pending.jsonl";
+
+/// A single point that failed to write, along with the device it belongs
+/// to, so it can be turned back into a `WriteQuery` on flush.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BacklogRecord {
+    pub device_id: String,
+    pub point: DataPoint,
+}
+
+pub struct Backlog {
+    path: PathBuf,
+    // Guards the backlog file against concurrent device workers appending
+    // failed writes, or an append racing a flush's replace, at the same
+    // time.
+    guard: Mutex<()>,
+}
+
+/// Serializes `records` as newline-delimited JSON into one buffer, so the
+/// file gets a single `write_all` rather than one syscall per record.
+fn encode(records: &[BacklogRecord]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut buf = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut buf, record)?;
+        buf.push(b'\n');
+    }
+    Ok(buf)
+}
+
+impl Backlog {
+    pub fn new(dir: &str) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(dir)?;
+        Ok(Backlog {
+            path: Path::new(dir).join(BACKLOG_FILE_NAME),
+            guard: Mutex::new(()),
+        })
+    }
+
+    /// Append records that failed to commit so they can be retried later.
+    pub fn append(&self, records: &[BacklogRecord]) -> Result<(), Box<dyn Error>> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let buf = encode(records)?;
+
+        let _guard = self.guard.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&buf)?;
+
+        Ok(())
+    }
+
+    /// All currently pending records, oldest first.
+    pub fn pending(&self) -> Result<Vec<BacklogRecord>, Box<dyn Error>> {
+        let _guard = self.guard.lock().unwrap();
+
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut records = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line)?);
+        }
+
+        Ok(records)
+    }
+
+    /// Atomically replace the backlog with exactly the given records, so a
+    /// crash mid-flush can't leave the file half-written.
+    pub fn replace(&self, records: &[BacklogRecord]) -> Result<(), Box<dyn Error>> {
+        let buf = encode(records)?;
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+
+        let _guard = self.guard.lock().unwrap();
+        fs::write(&tmp_path, &buf)?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}
@@ -12,22 +12,39 @@ extern crate log;
 use chrono::{DateTime, Duration, SecondsFormat, TimeZone, Utc};
 use envconfig::Envconfig;
 use failure::Fail;
-use futures::stream;
-use futures::stream::{StreamExt, TryStreamExt};
+use futures::stream::{self, StreamExt};
 use futures::TryFutureExt;
+use influxdb::Query;
 use reqwest::{StatusCode, Url};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Duration as TokioDuration};
+
+mod backlog;
+mod rules;
+mod state;
+
+use backlog::{Backlog, BacklogRecord};
+use rules::{parse_rules, RuleEngine};
+use state::State;
+
+/// The Awair raw air-data endpoint rejects queries spanning more than this;
+/// when backfilling a larger gap we split it into chunks of at most this
+/// size instead of making one oversized request.
+const MAX_QUERY_SPAN_HOURS: i64 = 24;
 
 #[derive(Envconfig)]
 struct Config {
     #[envconfig(from = "AWAIR_API_KEY")]
     pub api_key: String,
 
-    #[envconfig(from = "AWAIR_DEVICE_TYPE")]
-    pub device_type: String,
+    /// Comma-separated `device_type:device_id` pairs, e.g.
+    /// `awair-element:123,awair-omni:456`, one per device to monitor.
+    #[envconfig(from = "AWAIR_DEVICES")]
+    pub devices: String,
 
-    #[envconfig(from = "AWAIR_DEVICE_ID")]
-    pub device_id: String,
+    /// The maximum number of devices polled concurrently.
+    #[envconfig(from = "DEVICE_CONCURRENCY", default = "10")]
+    pub device_concurrency: usize,
 
     #[envconfig(from = "INFLUXDB_URL")]
     pub influx_db_url: String,
@@ -38,11 +55,169 @@ struct Config {
     #[envconfig(from = "INFLUXDB_PASSWORD", default = "")]
     pub influx_db_password: String,
 
-    #[envconfig(from = "INFLUXDB_DATABASE")]
+    #[envconfig(from = "INFLUXDB_DATABASE", default = "")]
     pub influx_db_database: String,
+
+    #[envconfig(from = "INFLUXDB_ORG")]
+    pub influx_db_org: Option<String>,
+
+    #[envconfig(from = "INFLUXDB_TOKEN")]
+    pub influx_db_token: Option<String>,
+
+    #[envconfig(from = "INFLUXDB_BUCKET")]
+    pub influx_db_bucket: Option<String>,
+
+    /// Maximum number of points batched into a single InfluxDB write
+    /// request. Set to `1` to fall back to one request per point.
+    #[envconfig(from = "INFLUXDB_BATCH_SIZE", default = "500")]
+    pub batch_size: usize,
+
+    #[envconfig(from = "BACKLOG_DIR")]
+    pub backlog_dir: Option<String>,
+
+    #[envconfig(from = "STATE_DIR")]
+    pub state_dir: Option<String>,
+
+    #[envconfig(from = "POLL_INTERVAL_SECS")]
+    pub poll_interval_secs: Option<u64>,
+
+    /// Comma-separated threshold rules, e.g. `co2>1000:300:600,pm25>35:300:600`.
+    #[envconfig(from = "RULES")]
+    pub rules: Option<String>,
+
+    #[envconfig(from = "WEBHOOK_URL")]
+    pub webhook_url: Option<String>,
+}
+
+impl Config {
+    /// Parses `AWAIR_DEVICES` into the list of devices to poll.
+    fn devices(&self) -> Result<Vec<Device>, Box<dyn Error>> {
+        self.devices
+            .split(',')
+            .map(str::trim)
+            .filter(|spec| !spec.is_empty())
+            .map(|spec| {
+                let mut parts = spec.splitn(2, ':');
+                let device_type = parts.next().unwrap_or_default();
+                let device_id = parts.next().ok_or_else(|| {
+                    format!("invalid AWAIR_DEVICES entry (expected type:id): {}", spec)
+                })?;
+
+                Ok(Device {
+                    device_type: device_type.to_string(),
+                    device_id: device_id.to_string(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// One Awair unit to poll, identified the same way the Awair API identifies
+/// it in its endpoint path.
+#[derive(Debug, Clone)]
+struct Device {
+    device_type: String,
+    device_id: String,
+}
+
+/// Which InfluxDB write path to speak, chosen by which env vars are set:
+/// `INFLUXDB_TOKEN` selects the 2.x `/api/v2/write` API, otherwise we fall
+/// back to the 1.x `influxdb::Client` (optionally with basic auth).
+enum InfluxDestination {
+    V1(influxdb::Client),
+    V2 {
+        http_client: reqwest::Client,
+        url: String,
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+impl InfluxDestination {
+    fn from_config(config: &Config) -> Result<Self, Box<dyn Error>> {
+        if let Some(token) = config.influx_db_token.as_ref().cloned() {
+            let org = config
+                .influx_db_org
+                .as_ref()
+                .cloned()
+                .ok_or("INFLUXDB_ORG is required when INFLUXDB_TOKEN is set")?;
+            let bucket = config
+                .influx_db_bucket
+                .as_ref()
+                .cloned()
+                .ok_or("INFLUXDB_BUCKET is required when INFLUXDB_TOKEN is set")?;
+
+            Ok(InfluxDestination::V2 {
+                http_client: reqwest::Client::new(),
+                url: config.influx_db_url.clone(),
+                org,
+                bucket,
+                token,
+            })
+        } else {
+            if config.influx_db_database.is_empty() {
+                return Err("INFLUXDB_DATABASE is required when INFLUXDB_TOKEN is not set".into());
+            }
+
+            let mut influxdb_client =
+                influxdb::Client::new(&config.influx_db_url, &config.influx_db_database);
+
+            if let Some(username) = config.influx_db_username.as_ref().cloned() {
+                let password = config.influx_db_password.clone();
+                influxdb_client = influxdb_client.with_auth(username, password);
+            }
+
+            Ok(InfluxDestination::V1(influxdb_client))
+        }
+    }
+
+    /// Writes a batch of points as a single request: one newline-delimited
+    /// line-protocol body rather than one round trip per point. A batch of
+    /// one point degenerates to the old per-point behaviour.
+    async fn write_batch(&self, queries: Vec<influxdb::WriteQuery>) -> Result<(), Box<dyn Error>> {
+        if queries.is_empty() {
+            return Ok(());
+        }
+
+        match self {
+            InfluxDestination::V1(client) => client
+                .query(queries)
+                .await
+                .map(|_| ())
+                .map_err(|err| Box::new(err.compat()) as Box<dyn Error>),
+            InfluxDestination::V2 {
+                http_client,
+                url,
+                org,
+                bucket,
+                token,
+            } => {
+                let line_protocol = queries.build()?.get();
+                let endpoint = format!("{}/api/v2/write", url);
+                let response = http_client
+                    .post(&endpoint)
+                    .query(&[
+                        ("org", org),
+                        ("bucket", bucket),
+                        ("precision", &"ns".to_string()),
+                    ])
+                    .header("Authorization", format!("Token {}", token))
+                    .body(line_protocol)
+                    .send()
+                    .await?;
+
+                if !response.status().is_success() {
+                    return Err(Box::new(InvalidResponse { response }));
+                }
+
+                Ok(())
+            }
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Eq, Hash, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, Hash, PartialEq)]
 enum MeasurementType {
     // Sensor: "temp"
     // Description: "Temperature"
@@ -104,17 +279,31 @@ impl MeasurementType {
             MeasurementType::PM25 => "PM25",
         }
     }
+
+    /// Parses the short sensor code used in the Awair API (and rule
+    /// expressions), e.g. `co2`, `pm25`.
+    fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "temp" => Some(MeasurementType::Temperature),
+            "humid" => Some(MeasurementType::Humidity),
+            "co2" => Some(MeasurementType::CO2),
+            "voc" => Some(MeasurementType::VOC),
+            "dust" => Some(MeasurementType::Dust),
+            "pm25" => Some(MeasurementType::PM25),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct Measurement {
     #[serde(rename = "comp")]
     kind: MeasurementType,
     value: f64,
 }
 
-#[derive(Debug, Deserialize)]
-struct DataPoint {
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct DataPoint {
     timestamp: DateTime<Utc>,
     score: f64,
     sensors: Box<[Measurement]>,
@@ -149,60 +338,154 @@ fn latest_complete_five_second_period() -> (DateTime<Utc>, DateTime<Utc>) {
     (lower, upper)
 }
 
+fn build_write_query(device_id: &str, measurement: &DataPoint) -> influxdb::WriteQuery {
+    let mut influxdb_measurement = influxdb::WriteQuery::new(measurement.timestamp.into(), "awair");
+
+    influxdb_measurement = influxdb_measurement.add_field("score", measurement.score);
+
+    for sensor_measurement in measurement.sensors.iter() {
+        let name = format!("{}.sensor", sensor_measurement.kind.field_name());
+        influxdb_measurement = influxdb_measurement.add_field(name, sensor_measurement.value);
+    }
+
+    for index_measurement in measurement.indices.iter() {
+        let name = format!("{}.index", index_measurement.kind.field_name());
+        influxdb_measurement = influxdb_measurement.add_field(name, index_measurement.value);
+    }
+
+    influxdb_measurement.add_tag("device_id", device_id.to_string())
+}
+
+/// Write freshly-fetched points, batched into requests of at most
+/// `batch_size` points each (`batch_size` of `1` falls back to one request
+/// per point). If a batch fails to commit and a backlog is configured,
+/// every point in that batch is queued for retry instead of failing the
+/// whole run; with no backlog configured the first failing batch aborts,
+/// as before.
 async fn post_to_influxdb<'a, I: Iterator<Item = &'a DataPoint>>(
-    config: Config,
+    destination: &InfluxDestination,
+    backlog: Option<&Backlog>,
+    batch_size: usize,
+    device_id: &str,
     measurements: I,
 ) -> Result<(), Box<dyn Error>> {
-    let mut influxdb_client =
-        influxdb::Client::new(&config.influx_db_url, &config.influx_db_database);
-
-    if let Some(username) = config.influx_db_username.as_ref().cloned() {
-        let password = config.influx_db_password.clone();
-        influxdb_client = influxdb_client.with_auth(username, password);
+    let points: Vec<&DataPoint> = measurements.collect();
+    let mut failed = Vec::new();
+
+    for chunk in points.chunks(batch_size.max(1)) {
+        let queries = chunk
+            .iter()
+            .copied()
+            .map(|point| build_write_query(device_id, point))
+            .collect();
+
+        if let Err(err) = destination.write_batch(queries).await {
+            match backlog {
+                Some(_) => {
+                    warn!(
+                        "failed to write batch of {} point(s) to InfluxDB, queuing to backlog: {}",
+                        chunk.len(),
+                        err
+                    );
+                    failed.extend(chunk.iter().copied().map(|point| BacklogRecord {
+                        device_id: device_id.to_string(),
+                        point: point.clone(),
+                    }));
+                }
+                None => return Err(err),
+            }
+        }
     }
 
-    let influx_db_client = &influxdb_client;
+    if let Some(backlog) = backlog {
+        backlog.append(&failed)?;
+    }
 
-    let influx_db_measurements = measurements.map(|measurement| {
-        let mut influxdb_measurement =
-            influxdb::WriteQuery::new(measurement.timestamp.into(), "awair");
+    Ok(())
+}
 
-        influxdb_measurement = influxdb_measurement.add_field("score", measurement.score);
+/// Retry whatever is still pending in the backlog, oldest first, before we
+/// write any freshly fetched data. Pending records are flushed in batches
+/// of at most `batch_size`, same as a fresh write; one bad batch doesn't
+/// block the rest.
+async fn flush_backlog(
+    destination: &InfluxDestination,
+    backlog: &Backlog,
+    batch_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    let pending = backlog.pending()?;
+    if pending.is_empty() {
+        return Ok(());
+    }
 
-        for sensor_measurement in measurement.sensors.iter() {
-            let name = format!("{}.sensor", sensor_measurement.kind.field_name());
-            influxdb_measurement = influxdb_measurement.add_field(name, sensor_measurement.value);
+    debug!("flushing {} backlogged record(s)", pending.len());
+
+    let mut remaining = Vec::new();
+    for chunk in pending.chunks(batch_size.max(1)) {
+        let queries = chunk
+            .iter()
+            .map(|record| build_write_query(&record.device_id, &record.point))
+            .collect();
+
+        if let Err(err) = destination.write_batch(queries).await {
+            warn!(
+                "failed to flush batch of {} backlogged record(s), will retry later: {}",
+                chunk.len(),
+                err
+            );
+            remaining.extend_from_slice(chunk);
         }
+    }
 
-        for index_measurement in measurement.indices.iter() {
-            let name = format!("{}.index", index_measurement.kind.field_name());
-            influxdb_measurement = influxdb_measurement.add_field(name, index_measurement.value);
-        }
+    backlog.replace(&remaining)
+}
 
-        let device_id = config.device_id.clone();
-        influxdb_measurement = influxdb_measurement.add_tag("device_id", device_id);
-        influxdb_measurement
-    });
+/// Split `(earliest, upper)` into contiguous windows of at most
+/// `MAX_QUERY_SPAN_HOURS`, in chronological order, so a long gap (process
+/// downtime, first run) is backfilled as several requests instead of one
+/// that the API would reject.
+fn compute_windows(
+    earliest: DateTime<Utc>,
+    upper: DateTime<Utc>,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let max_span = Duration::hours(MAX_QUERY_SPAN_HOURS);
+    let mut windows = Vec::new();
+    let mut from = earliest;
+
+    while from < upper {
+        let to = std::cmp::min(from + max_span, upper);
+        windows.push((from, to));
+        from = to;
+    }
 
-    stream::iter(influx_db_measurements)
-        .map(Ok)
-        .try_for_each_concurrent(10, |measurement| async move {
-            influx_db_client
-                .query(&measurement)
-                .await
-                .map(|_| ())
-                .map_err(|err| Box::new(err.compat()) as Box<dyn Error>)
-        })
-        .await
+    windows
 }
 
-async fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let (from, to) = latest_complete_five_second_period();
-    debug!("fetching data from: {} to: {}", from, to);
+/// Everything shared across devices and poll iterations: the HTTP and
+/// InfluxDB clients, and the optional backlog/state/rule subsystems.
+struct PollContext<'a> {
+    config: &'a Config,
+    http_client: &'a reqwest::Client,
+    destination: &'a InfluxDestination,
+    backlog: Option<&'a Backlog>,
+    state: Option<&'a State>,
+    rule_engine: Option<&'a RuleEngine>,
+}
+
+async fn fetch_and_write(
+    ctx: &PollContext<'_>,
+    device: &Device,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<(), Box<dyn Error>> {
+    debug!(
+        "fetching data for device {} from: {} to: {}",
+        device.device_id, from, to
+    );
 
     let endpoint = format!(
         "https://developer-apis.awair.is/v1/users/self/devices/{}/{}/air-data/raw",
-        config.device_type, config.device_id
+        device.device_type, device.device_id
     );
     let params = [
         ("from", from.to_rfc3339_opts(SecondsFormat::Secs, true)),
@@ -211,8 +494,7 @@ async fn run(config: Config) -> Result<(), Box<dyn Error>> {
 
     let url = Url::parse_with_params(&endpoint, &params)?;
 
-    let client = reqwest::Client::new();
-    let request = client.get(url).bearer_auth(&config.api_key);
+    let request = ctx.http_client.get(url).bearer_auth(&ctx.config.api_key);
 
     let response = request.send().await?;
 
@@ -222,11 +504,136 @@ async fn run(config: Config) -> Result<(), Box<dyn Error>> {
 
     let payload: Response = response.json().await?;
 
-    post_to_influxdb(config, payload.data.iter()).await?;
+    post_to_influxdb(
+        ctx.destination,
+        ctx.backlog,
+        ctx.config.batch_size,
+        &device.device_id,
+        payload.data.iter(),
+    )
+    .await?;
+
+    // Rules run after the write so a flaky webhook can't keep sensor data
+    // from reaching InfluxDB (or the backlog); a failed notification is
+    // logged and otherwise ignored rather than failing the whole poll.
+    if let Some(rule_engine) = ctx.rule_engine {
+        for point in payload.data.iter() {
+            if let Err(err) = rule_engine.evaluate_point(&device.device_id, point).await {
+                warn!(
+                    "failed to evaluate rules for device {}: {}",
+                    device.device_id, err
+                );
+            }
+        }
+    }
 
     Ok(())
 }
 
+/// Fetch and write everything between this device's last confirmed
+/// high-water-mark (or the start of the most recent complete period, if
+/// this is the first run) and the end of the most recent complete period,
+/// saving the high-water-mark after each
+/// window so a restart mid-backfill resumes rather than re-queries from
+/// scratch.
+async fn poll_device(ctx: &PollContext<'_>, device: &Device) -> Result<(), Box<dyn Error>> {
+    let (default_earliest, upper) = latest_complete_five_second_period();
+    let earliest = ctx
+        .state
+        .map(|state| state.load(&device.device_id))
+        .transpose()?
+        .flatten()
+        .unwrap_or(default_earliest);
+
+    let windows = compute_windows(earliest, upper);
+    if windows.is_empty() {
+        debug!("no new data to fetch for device {}", device.device_id);
+        return Ok(());
+    }
+
+    for (from, to) in windows {
+        fetch_and_write(ctx, device, from, to).await?;
+        if let Some(state) = ctx.state {
+            state.save(&device.device_id, to)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Poll every configured device concurrently (bounded by
+/// `device_concurrency`). A single device's failure is logged and does not
+/// stop the others from polling, and a failed backlog flush is logged and
+/// does not stop fresh data from being collected this tick.
+async fn poll_all(ctx: &PollContext<'_>, devices: &[Device]) -> Result<(), Box<dyn Error>> {
+    if let Some(backlog) = ctx.backlog {
+        if let Err(err) = flush_backlog(ctx.destination, backlog, ctx.config.batch_size).await {
+            error!(
+                "failed to flush backlog, will retry next tick; polling devices anyway: {}",
+                err
+            );
+        }
+    }
+
+    stream::iter(devices)
+        .map(|device| async move {
+            if let Err(err) = poll_device(ctx, device).await {
+                error!("failed to poll device {}: {}", device.device_id, err);
+            }
+        })
+        .buffer_unordered(ctx.config.device_concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    Ok(())
+}
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let devices = config.devices()?;
+    let http_client = reqwest::Client::new();
+    let destination = InfluxDestination::from_config(&config)?;
+    let backlog = config
+        .backlog_dir
+        .as_ref()
+        .map(|dir| Backlog::new(dir))
+        .transpose()?;
+    let state = config
+        .state_dir
+        .as_ref()
+        .or(config.backlog_dir.as_ref())
+        .map(|dir| State::new(dir))
+        .transpose()?;
+    let rule_engine = match (&config.rules, &config.webhook_url) {
+        (Some(rules), Some(webhook_url)) => {
+            Some(RuleEngine::new(parse_rules(rules)?, webhook_url.clone()))
+        }
+        (None, None) => None,
+        _ => return Err("RULES and WEBHOOK_URL must be set together".into()),
+    };
+
+    let ctx = PollContext {
+        config: &config,
+        http_client: &http_client,
+        destination: &destination,
+        backlog: backlog.as_ref(),
+        state: state.as_ref(),
+        rule_engine: rule_engine.as_ref(),
+    };
+
+    match config.poll_interval_secs {
+        Some(poll_interval_secs) => {
+            let mut interval = time::interval(TokioDuration::from_secs(poll_interval_secs));
+            loop {
+                interval.tick().await;
+                if let Err(err) = poll_all(&ctx, &devices).await {
+                    error!("poll failed, will retry next interval: {}", err);
+                }
+            }
+        }
+        None => poll_all(&ctx, &devices).await,
+    }
+}
+
 async fn load_config() -> Result<Config, Box<dyn Error>> {
     Config::init().map_err(|err| Box::new(err) as Box<dyn Error>)
 }
@@ -245,3 +652,40 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::compute_windows;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn compute_windows_splits_long_gaps_into_max_span_chunks() {
+        let earliest = Utc.ymd(2024, 1, 1).and_hms(0, 0, 0);
+        let upper = Utc.ymd(2024, 1, 3).and_hms(6, 0, 0);
+
+        let windows = compute_windows(earliest, upper);
+
+        assert_eq!(
+            windows,
+            vec![
+                (earliest, Utc.ymd(2024, 1, 2).and_hms(0, 0, 0)),
+                (Utc.ymd(2024, 1, 2).and_hms(0, 0, 0), Utc.ymd(2024, 1, 3).and_hms(0, 0, 0)),
+                (Utc.ymd(2024, 1, 3).and_hms(0, 0, 0), upper),
+            ]
+        );
+    }
+
+    #[test]
+    fn compute_windows_keeps_a_short_gap_as_one_window() {
+        let earliest = Utc.ymd(2024, 1, 1).and_hms(0, 0, 0);
+        let upper = Utc.ymd(2024, 1, 1).and_hms(0, 5, 0);
+
+        assert_eq!(compute_windows(earliest, upper), vec![(earliest, upper)]);
+    }
+
+    #[test]
+    fn compute_windows_is_empty_when_there_is_nothing_new() {
+        let now = Utc.ymd(2024, 1, 1).and_hms(0, 0, 0);
+        assert!(compute_windows(now, now).is_empty());
+    }
+}
@@ -0,0 +1,409 @@
+//! A small threshold rule engine layered over polled sensor data. A rule
+//! like `co2>1000` fires a webhook once its condition has held for a
+//! sustained duration, and fires a second "cleared" webhook once the value
+//! recovers. A cooldown after firing keeps a flapping sensor from spamming
+//! the webhook.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::{DataPoint, InvalidResponse, MeasurementType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    GreaterThan,
+    LessThan,
+}
+
+impl Comparison {
+    fn evaluate(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::GreaterThan => value > threshold,
+            Comparison::LessThan => value < threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    sensor: MeasurementType,
+    comparison: Comparison,
+    threshold: f64,
+    sustained: Duration,
+    cooldown: Duration,
+}
+
+/// Parses `RULES` entries of the form `<sensor><op><threshold>:<sustained
+/// secs>:<cooldown secs>`, e.g. `co2>1000:300:600` (CO2 above 1000ppm for 5
+/// minutes fires, then won't re-fire for 10 minutes).
+pub fn parse_rules(raw: &str) -> Result<Vec<Rule>, Box<dyn Error>> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(parse_rule)
+        .collect()
+}
+
+fn parse_rule(entry: &str) -> Result<Rule, Box<dyn Error>> {
+    let mut fields = entry.splitn(3, ':');
+    let condition = fields
+        .next()
+        .ok_or_else(|| format!("invalid rule (missing condition): {}", entry))?;
+    let sustained_secs: i64 = fields
+        .next()
+        .ok_or_else(|| format!("invalid rule (missing sustained-duration secs): {}", entry))?
+        .parse()?;
+    let cooldown_secs: i64 = fields
+        .next()
+        .ok_or_else(|| format!("invalid rule (missing cooldown secs): {}", entry))?
+        .parse()?;
+
+    let (comparison, split_at) = if let Some(idx) = condition.find('>') {
+        (Comparison::GreaterThan, idx)
+    } else if let Some(idx) = condition.find('<') {
+        (Comparison::LessThan, idx)
+    } else {
+        return Err(format!(
+            "invalid rule condition (expected sensor>value or sensor<value): {}",
+            condition
+        )
+        .into());
+    };
+
+    let sensor_code = &condition[..split_at];
+    let threshold: f64 = condition[split_at + 1..].parse()?;
+    let sensor = MeasurementType::from_code(sensor_code)
+        .ok_or_else(|| format!("unknown sensor in rule: {}", sensor_code))?;
+
+    Ok(Rule {
+        sensor,
+        comparison,
+        threshold,
+        sustained: Duration::seconds(sustained_secs),
+        cooldown: Duration::seconds(cooldown_secs),
+    })
+}
+
+#[derive(Default)]
+struct RuleRuntimeState {
+    breach_started_at: Option<DateTime<Utc>>,
+    firing: bool,
+    last_fired_at: Option<DateTime<Utc>>,
+}
+
+/// Which way a rule just transitioned, pending webhook delivery.
+#[derive(Debug, Clone, Copy)]
+enum TransitionKind {
+    Fired,
+    Cleared,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload {
+    event: &'static str,
+    device_id: String,
+    sensor: &'static str,
+    value: f64,
+    threshold: f64,
+    timestamp: DateTime<Utc>,
+}
+
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+    webhook_url: String,
+    http_client: reqwest::Client,
+    state: Mutex<HashMap<(usize, String), RuleRuntimeState>>,
+}
+
+impl RuleEngine {
+    pub fn new(rules: Vec<Rule>, webhook_url: String) -> Self {
+        RuleEngine {
+            rules,
+            webhook_url,
+            http_client: reqwest::Client::new(),
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Evaluates every rule against a freshly-fetched point for `device_id`,
+    /// firing or clearing webhooks as needed. A webhook failure for one
+    /// rule is logged and doesn't stop the rest of the rules from being
+    /// evaluated for this point.
+    pub async fn evaluate_point(
+        &self,
+        device_id: &str,
+        point: &DataPoint,
+    ) -> Result<(), Box<dyn Error>> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            let value = point
+                .sensors
+                .iter()
+                .chain(point.indices.iter())
+                .find(|measurement| measurement.kind == rule.sensor);
+
+            let value = match value {
+                Some(measurement) => measurement.value,
+                None => continue,
+            };
+
+            let pending = self.check_transition(index, rule, device_id, value, point.timestamp);
+            let Some((kind, event)) = pending else {
+                continue;
+            };
+
+            match self.post_webhook(&event).await {
+                Ok(()) => self.commit_transition(index, device_id, kind, point.timestamp),
+                Err(err) => warn!(
+                    "failed to post {} webhook for device {} rule {}, will retry next time the \
+                     condition is evaluated: {}",
+                    event.event, device_id, index, err
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether `rule` has just transitioned into firing or clearing
+    /// for this device, without committing that transition -- callers must
+    /// call `commit_transition` once the resulting webhook has actually
+    /// been delivered, so a failed POST is retried next time rather than
+    /// silently recorded as sent.
+    fn check_transition(
+        &self,
+        rule_index: usize,
+        rule: &Rule,
+        device_id: &str,
+        value: f64,
+        timestamp: DateTime<Utc>,
+    ) -> Option<(TransitionKind, WebhookPayload)> {
+        let mut states = self.state.lock().unwrap();
+        let entry = states
+            .entry((rule_index, device_id.to_string()))
+            .or_default();
+
+        let breaching = rule.comparison.evaluate(value, rule.threshold);
+
+        if breaching {
+            let breach_started_at = *entry.breach_started_at.get_or_insert(timestamp);
+            let sustained_for = timestamp - breach_started_at;
+            let in_cooldown = entry
+                .last_fired_at
+                .is_some_and(|last_fired_at| timestamp - last_fired_at < rule.cooldown);
+
+            if !entry.firing && sustained_for >= rule.sustained && !in_cooldown {
+                return Some((
+                    TransitionKind::Fired,
+                    WebhookPayload {
+                        event: "fired",
+                        device_id: device_id.to_string(),
+                        sensor: rule.sensor.field_name(),
+                        value,
+                        threshold: rule.threshold,
+                        timestamp,
+                    },
+                ));
+            }
+        } else {
+            entry.breach_started_at = None;
+            if entry.firing {
+                return Some((
+                    TransitionKind::Cleared,
+                    WebhookPayload {
+                        event: "cleared",
+                        device_id: device_id.to_string(),
+                        sensor: rule.sensor.field_name(),
+                        value,
+                        threshold: rule.threshold,
+                        timestamp,
+                    },
+                ));
+            }
+        }
+
+        None
+    }
+
+    /// Records a transition found by `check_transition` once its webhook
+    /// has been confirmed delivered.
+    fn commit_transition(
+        &self,
+        rule_index: usize,
+        device_id: &str,
+        kind: TransitionKind,
+        timestamp: DateTime<Utc>,
+    ) {
+        let mut states = self.state.lock().unwrap();
+        let entry = states
+            .entry((rule_index, device_id.to_string()))
+            .or_default();
+
+        match kind {
+            TransitionKind::Fired => {
+                entry.firing = true;
+                entry.last_fired_at = Some(timestamp);
+            }
+            TransitionKind::Cleared => entry.firing = false,
+        }
+    }
+
+    async fn post_webhook(&self, payload: &WebhookPayload) -> Result<(), Box<dyn Error>> {
+        let response = self
+            .http_client
+            .post(&self.webhook_url)
+            .json(payload)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Box::new(InvalidResponse { response }));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parse_rule_parses_condition_sustained_and_cooldown() {
+        let rule = parse_rule("co2>1000:300:600").unwrap();
+
+        assert_eq!(rule.sensor, MeasurementType::CO2);
+        assert_eq!(rule.comparison, Comparison::GreaterThan);
+        assert_eq!(rule.threshold, 1000.0);
+        assert_eq!(rule.sustained, Duration::seconds(300));
+        assert_eq!(rule.cooldown, Duration::seconds(600));
+    }
+
+    #[test]
+    fn parse_rule_rejects_missing_fields() {
+        assert!(parse_rule("co2>1000:300").is_err());
+    }
+
+    #[test]
+    fn parse_rule_rejects_unknown_sensor() {
+        assert!(parse_rule("bogus>1000:300:600").is_err());
+    }
+
+    #[test]
+    fn parse_rule_rejects_condition_without_an_operator() {
+        assert!(parse_rule("co21000:300:600").is_err());
+    }
+
+    fn rule(sustained_secs: i64, cooldown_secs: i64) -> Rule {
+        Rule {
+            sensor: MeasurementType::CO2,
+            comparison: Comparison::GreaterThan,
+            threshold: 1000.0,
+            sustained: Duration::seconds(sustained_secs),
+            cooldown: Duration::seconds(cooldown_secs),
+        }
+    }
+
+    /// Mirrors what `evaluate_point` does on a successful webhook POST:
+    /// check for a transition, and immediately commit it.
+    fn transition(
+        engine: &RuleEngine,
+        rule_index: usize,
+        rule: &Rule,
+        device_id: &str,
+        value: f64,
+        timestamp: DateTime<Utc>,
+    ) -> Option<WebhookPayload> {
+        let (kind, event) = engine.check_transition(rule_index, rule, device_id, value, timestamp)?;
+        engine.commit_transition(rule_index, device_id, kind, timestamp);
+        Some(event)
+    }
+
+    #[test]
+    fn transition_does_not_fire_until_breach_is_sustained() {
+        let engine = RuleEngine::new(Vec::new(), "http://webhook.invalid".to_string());
+        let rule = rule(300, 600);
+        let t0 = Utc.ymd(2024, 1, 1).and_hms(0, 0, 0);
+
+        assert!(transition(&engine, 0, &rule, "device-1", 1200.0, t0).is_none());
+        assert!(
+            transition(&engine, 0, &rule, "device-1", 1200.0, t0 + Duration::seconds(100))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn transition_fires_once_sustained_then_clears_on_recovery() {
+        let engine = RuleEngine::new(Vec::new(), "http://webhook.invalid".to_string());
+        let rule = rule(300, 600);
+        let t0 = Utc.ymd(2024, 1, 1).and_hms(0, 0, 0);
+
+        transition(&engine, 0, &rule, "device-1", 1200.0, t0);
+        let fired = transition(&engine, 0, &rule, "device-1", 1200.0, t0 + Duration::seconds(300))
+            .expect("rule should fire once sustained for its threshold duration");
+        assert_eq!(fired.event, "fired");
+
+        // Still breaching: already firing, so no repeat webhook.
+        assert!(
+            transition(&engine, 0, &rule, "device-1", 1200.0, t0 + Duration::seconds(400))
+                .is_none()
+        );
+
+        let cleared = transition(&engine, 0, &rule, "device-1", 400.0, t0 + Duration::seconds(500))
+            .expect("rule should clear once the value recovers");
+        assert_eq!(cleared.event, "cleared");
+    }
+
+    #[test]
+    fn transition_respects_cooldown_after_firing() {
+        let engine = RuleEngine::new(Vec::new(), "http://webhook.invalid".to_string());
+        let rule = rule(300, 600);
+        let t0 = Utc.ymd(2024, 1, 1).and_hms(0, 0, 0);
+
+        transition(&engine, 0, &rule, "device-1", 1200.0, t0);
+        transition(&engine, 0, &rule, "device-1", 1200.0, t0 + Duration::seconds(300))
+            .expect("rule should fire once sustained for its threshold duration");
+        transition(&engine, 0, &rule, "device-1", 400.0, t0 + Duration::seconds(310));
+
+        // A new, equally-sustained breach starting right after the clear
+        // would otherwise re-fire at t0+620, but that's still inside the
+        // 600s cooldown from the first firing at t0+300.
+        transition(&engine, 0, &rule, "device-1", 1200.0, t0 + Duration::seconds(320));
+        assert!(
+            transition(&engine, 0, &rule, "device-1", 1200.0, t0 + Duration::seconds(620))
+                .is_none()
+        );
+
+        // Once the cooldown has elapsed, the still-sustained breach fires.
+        let fired = transition(&engine, 0, &rule, "device-1", 1200.0, t0 + Duration::seconds(920))
+            .expect("rule should fire again once the cooldown has elapsed");
+        assert_eq!(fired.event, "fired");
+    }
+
+    #[test]
+    fn a_failed_webhook_is_not_recorded_as_delivered() {
+        let engine = RuleEngine::new(Vec::new(), "http://webhook.invalid".to_string());
+        let rule = rule(300, 600);
+        let t0 = Utc.ymd(2024, 1, 1).and_hms(0, 0, 0);
+
+        transition(&engine, 0, &rule, "device-1", 1200.0, t0);
+
+        // Simulate a webhook POST that fails: check the transition but
+        // never commit it, exactly like `evaluate_point`'s `Err` arm.
+        let (kind, _event) = engine
+            .check_transition(0, &rule, "device-1", 1200.0, t0 + Duration::seconds(300))
+            .expect("rule should be ready to fire once sustained for its threshold duration");
+        assert!(matches!(kind, TransitionKind::Fired));
+
+        // Without a commit, the engine still believes it hasn't fired, so
+        // the next tick finds the same pending "fired" transition again.
+        let retried = engine
+            .check_transition(0, &rule, "device-1", 1200.0, t0 + Duration::seconds(310))
+            .expect("an uncommitted transition should be retried, not forgotten");
+        assert!(matches!(retried.0, TransitionKind::Fired));
+    }
+}
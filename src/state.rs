@@ -0,0 +1,60 @@
+//! Persists, per device, the end of the last query window whose points were
+//! written (or queued to the backlog), so daemon mode can resume backfilling each device from where it
+//! left off after a restart instead of re-querying from scratch or leaving
+//! a gap.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+const STATE_FILE_NAME: &str = "high_water_marks.json";
+
+pub struct State {
+    path: PathBuf,
+    // Guards read-modify-write of the state file against concurrent device
+    // workers saving their high-water-mark at the same time.
+    guard: Mutex<()>,
+}
+
+impl State {
+    pub fn new(dir: &str) -> Result<Self, Box<dyn Error>> {
+        fs::create_dir_all(dir)?;
+        Ok(State {
+            path: Path::new(dir).join(STATE_FILE_NAME),
+            guard: Mutex::new(()),
+        })
+    }
+
+    fn read_all(&self) -> Result<HashMap<String, DateTime<Utc>>, Box<dyn Error>> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let contents = fs::read_to_string(&self.path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// The timestamp we last confirmed writing up to for this device, if any.
+    pub fn load(&self, device_id: &str) -> Result<Option<DateTime<Utc>>, Box<dyn Error>> {
+        let _guard = self.guard.lock().unwrap();
+        Ok(self.read_all()?.get(device_id).copied())
+    }
+
+    /// Writes to a temp file and renames it into place, so a crash
+    /// mid-write can't leave `high_water_marks.json` truncated and
+    /// unparsable on the next `load`.
+    pub fn save(&self, device_id: &str, last_written: DateTime<Utc>) -> Result<(), Box<dyn Error>> {
+        let _guard = self.guard.lock().unwrap();
+        let mut all = self.read_all()?;
+        all.insert(device_id.to_string(), last_written);
+
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string(&all)?)?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+}